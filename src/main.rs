@@ -1,19 +1,16 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use console::{style, Style};
 use core::cmp::Ordering;
 use hdrhistogram::Histogram;
 use rayon::prelude::*;
 use regex::Regex;
 use std::cmp::PartialOrd;
-use std::convert::From;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{self, BufRead, Write, BufWriter, BufReader};
 use std::path::PathBuf;
 use structopt::{clap::arg_enum, StructOpt};
 
-// TODO(ckonstad)
-//  -context? (can we sort + context?)
-
 arg_enum! {
     #[derive(Copy, Clone, Debug, PartialEq, Eq)]
     enum Sorting {
@@ -24,16 +21,17 @@ arg_enum! {
 }
 
 #[derive(Debug, PartialEq)]
-enum Data {
+enum Data<'a> {
     Matching {
-        line: String,
+        line: &'a str,
         range: std::ops::Range<usize>,
         parsed: u64,
+        key: Option<String>,
     },
-    NotMatching(String),
+    NotMatching(&'a str),
 }
 
-impl PartialOrd for Data {
+impl<'a> PartialOrd for Data<'a> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         let a = match self {
             Data::Matching { parsed, .. } => Some(parsed),
@@ -51,23 +49,75 @@ impl PartialOrd for Data {
     }
 }
 
-#[derive(Debug)]
-enum Percentile {
-    P99,
-    P90,
-    P50,
-    Other,
+/// Colors cycled through for each quantile bucket `val` meets or exceeds.
+fn bucket_style(i: usize) -> Style {
+    match i % 5 {
+        0 => Style::new().red(),
+        1 => Style::new().yellow(),
+        2 => Style::new().green(),
+        3 => Style::new().cyan(),
+        _ => Style::new().magenta(),
+    }
 }
 
-impl From<Percentile> for Style {
-    fn from(p: Percentile) -> Self {
-        match p {
-            Percentile::P99 => Style::new().red(),
-            Percentile::P90 => Style::new().yellow(),
-            Percentile::P50 => Style::new().green(),
-            Percentile::Other => Style::new().blue(),
-        }
+/// Style for a value below every configured quantile threshold.
+fn below_all_style() -> Style {
+    Style::new().blue()
+}
+
+/// Quantile→value ladder, highest quantile first, used for coloring.
+fn build_thresholds(hist: &Histogram<u64>, quantiles: &[f64]) -> Vec<(f64, u64)> {
+    let mut quantiles = quantiles.to_vec();
+    quantiles.sort_by(|a, b| b.partial_cmp(a).unwrap());
+    quantiles.dedup();
+    quantiles
+        .iter()
+        .map(|&q| (q, hist.value_at_quantile(q)))
+        .collect()
+}
+
+/// Pick the `Style` for `val` given the ladder from `build_thresholds`.
+fn style_for_value(val: u64, thresholds: &[(f64, u64)]) -> Style {
+    thresholds
+        .iter()
+        .position(|(_, threshold)| *threshold <= val)
+        .map(bucket_style)
+        .unwrap_or_else(below_all_style)
+}
+
+/// Convert an HSL color (h in degrees, s and l in [0, 1]) to 8-bit RGB.
+fn hsl_to_rgb(h: f64, s: f64, l: f64) -> (u8, u8, u8) {
+    let c = (1.0 - (2.0 * l - 1.0).abs()) * s;
+    let x = c * (1.0 - ((h / 60.0) % 2.0 - 1.0).abs());
+    let m = l - c / 2.0;
+    let (r1, g1, b1) = match (h / 60.0) as u64 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    (
+        ((r1 + m) * 255.0).round() as u8,
+        ((g1 + m) * 255.0).round() as u8,
+        ((b1 + m) * 255.0).round() as u8,
+    )
+}
+
+/// Map a quantile in [0, 1] onto a green-to-red gradient.
+fn quantile_to_rgb(q: f64) -> (u8, u8, u8) {
+    let h = (1.0 - q) * 120.0;
+    hsl_to_rgb(h, 0.9, 0.5)
+}
+
+/// Wrap `text` in a 24-bit truecolor ANSI escape.
+fn truecolor(text: &str, (r, g, b): (u8, u8, u8), bold: bool) -> String {
+    if !console::colors_enabled() {
+        return text.to_string();
     }
+    let bold = if bold { "1;" } else { "" };
+    format!("\x1b[{}38;2;{};{};{}m{}\x1b[0m", bold, r, g, b, text)
 }
 
 #[derive(Debug, StructOpt)]
@@ -107,16 +157,108 @@ struct Opt {
         default_value="original",
     )]
     sorting: Sorting,
+
+    /// Regex capture group index holding a grouping key (e.g. a hostname or
+    /// endpoint).  When set, logscanner aggregates per-key stats instead of
+    /// coloring the input, and prints one JSON line per key.
+    #[structopt(long)]
+    group: Option<usize>,
+
+    /// If we should use a continuous truecolor heatmap instead of the
+    /// --quantiles percentile buckets.  This disables the bucketed heatmap.
+    #[structopt(long)]
+    gradient: bool,
+
+    /// Memory-map the input file instead of reading it into owned `String`s.
+    /// This is automatic when `--input` names a regular file; pass this to
+    /// force it for other file types (e.g. a FIFO).
+    #[structopt(long)]
+    mmap: bool,
+
+    /// In --matching mode, also print N non-matching lines after each match.
+    /// Only meaningful with the default --sorting original.
+    #[structopt(short = "A", long = "after", default_value = "0")]
+    after_context: usize,
+
+    /// In --matching mode, also print N non-matching lines before each
+    /// match.  Only meaningful with the default --sorting original.
+    #[structopt(short = "B", long = "before", default_value = "0")]
+    before_context: usize,
+
+    /// Shorthand for --after N --before N.  Only meaningful with the
+    /// default --sorting original.
+    #[structopt(short = "C", long = "context", default_value = "0")]
+    context: usize,
+
+    /// Comma-separated quantiles (0.0-1.0) driving both the --debug stats
+    /// table and the percentile buckets used for coloring, e.g.
+    /// "0.5,0.75,0.9,0.99,0.999"
+    #[structopt(long, default_value = "0.99,0.9,0.5", use_delimiter = true)]
+    quantiles: Vec<f64>,
+}
+
+/// Backing storage for the input lines, owned `String`s or an mmap.
+enum LineSource {
+    Owned(Vec<String>),
+    Mapped(memmap2::Mmap),
+}
+
+impl LineSource {
+    fn lines(&self) -> Vec<&str> {
+        match self {
+            LineSource::Owned(v) => v.iter().map(String::as_str).collect(),
+            LineSource::Mapped(m) => mmap_lines(m),
+        }
+    }
+}
+
+/// Strip a single trailing `\r`, matching `std::io::BufRead::lines()`.
+fn trim_trailing_cr(line: &str) -> &str {
+    line.strip_suffix('\r').unwrap_or(line)
 }
 
-fn match_line(re: &Regex, line: String) -> Data {
-    if let Some(captures) = re.captures(&line) {
+/// Split a memory-mapped byte region into `&str` line slices.
+fn mmap_lines(m: &[u8]) -> Vec<&str> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for pos in memchr::memchr_iter(b'\n', m) {
+        lines.push(trim_trailing_cr(std::str::from_utf8(&m[start..pos]).unwrap()));
+        start = pos + 1;
+    }
+    // Like `lines()`, a final line with no `\n` keeps any trailing `\r`.
+    if start < m.len() {
+        lines.push(std::str::from_utf8(&m[start..]).unwrap());
+    }
+    lines
+}
+
+/// Reject a `--group` index `re` could never populate.
+fn validate_group(re: &Regex, group: Option<usize>) -> Result<()> {
+    if let Some(g) = group {
+        let captures_len = re.captures_len();
+        if g >= captures_len {
+            bail!(
+                "--group {} is out of range for this expression, which has {} capture group(s)",
+                g,
+                captures_len.saturating_sub(1),
+            );
+        }
+    }
+    Ok(())
+}
+
+fn match_line<'a>(re: &Regex, line: &'a str, group: Option<usize>) -> Data<'a> {
+    if let Some(captures) = re.captures(line) {
         if let Some(m) = captures.get(1) {
             if let Ok(f) = line[m.start()..m.end()].parse::<u64>() {
+                let key = group
+                    .and_then(|g| captures.get(g))
+                    .map(|k| k.as_str().to_string());
                 return Data::Matching {
                     range: m.range(),
                     line,
                     parsed: f,
+                    key,
                 };
             }
         }
@@ -124,13 +266,270 @@ fn match_line(re: &Regex, line: String) -> Data {
     Data::NotMatching(line)
 }
 
-fn filter_and_sort(data: Vec<Data>, matching: bool, sorting: Sorting) -> Vec<Data> {
+/// Build a per-key `Histogram` map from already-matched data.
+fn group_histograms(data: &[Data<'_>]) -> Result<HashMap<String, Histogram<u64>>> {
+    data.par_iter()
+        .fold(
+            || Ok(HashMap::<String, Histogram<u64>>::new()),
+            |acc, d| {
+                let mut acc = acc?;
+                if let Data::Matching {
+                    key: Some(k),
+                    parsed,
+                    ..
+                } = d
+                {
+                    let h = match acc.get_mut(k) {
+                        Some(h) => h,
+                        None => {
+                            acc.insert(k.clone(), Histogram::new(5)?);
+                            acc.get_mut(k).unwrap()
+                        }
+                    };
+                    *h += *parsed;
+                }
+                Ok(acc)
+            },
+        )
+        .reduce(
+            || Ok(HashMap::new()),
+            |a, b| {
+                let mut a = a?;
+                for (k, h) in b? {
+                    match a.get_mut(&k) {
+                        Some(existing) => existing.add(h)?,
+                        None => {
+                            a.insert(k, h);
+                        }
+                    }
+                }
+                Ok(a)
+            },
+        )
+}
+
+/// Format a single group's JSON report line.
+fn format_group_line(key: &str, h: &Histogram<u64>) -> String {
+    let mut quoted = String::with_capacity(key.len() + 2);
+    quoted.push('"');
+    for c in key.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    format!(
+        "{{\"key\":{},\"count\":{},\"min\":{},\"mean\":{:.2},\"p50\":{},\"p90\":{},\"p99\":{},\"max\":{}}}",
+        quoted,
+        h.len(),
+        h.min(),
+        h.mean(),
+        h.value_at_quantile(0.5),
+        h.value_at_quantile(0.9),
+        h.value_at_quantile(0.99),
+        h.max(),
+    )
+}
+
+/// Print one JSON line per key, sorted by key for stable output.
+fn print_group_report(grouped: &HashMap<String, Histogram<u64>>) -> Result<()> {
+    let mut keys: Vec<&String> = grouped.keys().collect();
+    keys.sort();
+
+    let stdout = io::stdout();
+    let mut out = BufWriter::with_capacity(8 * 1024 * 1024, stdout.lock());
+    for key in keys {
+        writeln!(out, "{}", format_group_line(key, &grouped[key]))?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Colorize a matched span; shared by the buffered and streaming paths.
+fn colorize(opt: &Opt, hist: &Histogram<u64>, thresholds: &[(f64, u64)], during: &str, parsed: u64) -> String {
+    if opt.gradient {
+        let q = hist.percentile_below(parsed) / 100.0;
+        truecolor(during, quantile_to_rgb(q), opt.bold)
+    } else {
+        let styled = match opt.highlight {
+            true => style(during).yellow(),
+            false => style_for_value(parsed, thresholds).apply_to(during),
+        };
+        let styled = match opt.bold {
+            true => styled.bold(),
+            false => styled,
+        };
+        styled.to_string()
+    }
+}
+
+/// Print the `--debug` stats report.
+fn print_debug_stats(hist: &Histogram<u64>, quantiles: &[f64]) {
+    println!("Number of samples: {}", hist.len());
+    println!("Min:                {}", hist.min());
+    println!("Max:                {}", hist.max());
+    println!("Mean:               {:.2}", hist.mean());
+    println!("Stdev:              {:.2}", hist.stdev());
+
+    let mut quantiles = quantiles.to_vec();
+    quantiles.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    quantiles.dedup();
+    for q in quantiles {
+        println!("p{:<6} {}", format!("{:.3}", q * 100.0), hist.value_at_quantile(q));
+    }
+}
+
+/// Scan a file once, building its `Histogram` without retaining any line.
+fn scan_histogram(path: &PathBuf, re: &Regex) -> Result<Histogram<u64>> {
+    let mut hist = Histogram::<u64>::new(5)?;
+    let f = File::open(path)?;
+    for line in io::BufReader::with_capacity(1024 * 1024, f).lines() {
+        let line = line?;
+        if let Data::Matching { parsed, .. } = match_line(re, &line, None) {
+            hist += parsed;
+        }
+    }
+    Ok(hist)
+}
+
+/// Two-pass streaming mode re-reading the file once per pass via `BufReader`.
+fn run_streaming_buffered(opt: &Opt, re: &Regex, file: &PathBuf) -> Result<()> {
+    let hist = scan_histogram(file, re)?;
+    let thresholds = build_thresholds(&hist, &opt.quantiles);
+
+    let f = File::open(file)?;
+    let stdout = io::stdout();
+    let mut out = BufWriter::with_capacity(8 * 1024 * 1024, stdout.lock());
+    for line in io::BufReader::with_capacity(1024 * 1024, f).lines() {
+        let line = line?;
+        match match_line(re, &line, None) {
+            Data::NotMatching(_) => {
+                if !opt.matching {
+                    writeln!(out, "{}", line)?;
+                }
+            }
+            Data::Matching { range, parsed, .. } => {
+                let before = &line[0..range.start];
+                let during = &line[range.clone()];
+                let during = colorize(opt, &hist, &thresholds, during, parsed);
+                let after = &line[range.end..];
+                writeln!(out, "{}{}{}", before, during, after)?;
+            }
+        }
+    }
+    out.flush()?;
+
+    if opt.debug {
+        print_debug_stats(&hist, &opt.quantiles);
+    }
+
+    Ok(())
+}
+
+/// Same as `run_streaming_buffered`, but walking `mmap_lines` over a mapping.
+fn run_streaming_mapped(opt: &Opt, re: &Regex, file: &PathBuf) -> Result<()> {
+    let f = File::open(file)?;
+    // SAFETY: see the matching comment on the buffer-everything path's mmap
+    // call in `main` -- same file-we-just-opened, read-only, no other
+    // process racing it from within this program.
+    let mapped = unsafe { memmap2::Mmap::map(&f)? };
+    let lines = mmap_lines(&mapped);
+
+    let mut hist = Histogram::<u64>::new(5)?;
+    for &line in &lines {
+        if let Data::Matching { parsed, .. } = match_line(re, line, None) {
+            hist += parsed;
+        }
+    }
+    let thresholds = build_thresholds(&hist, &opt.quantiles);
+
+    let stdout = io::stdout();
+    let mut out = BufWriter::with_capacity(8 * 1024 * 1024, stdout.lock());
+    for &line in &lines {
+        match match_line(re, line, None) {
+            Data::NotMatching(_) => {
+                if !opt.matching {
+                    writeln!(out, "{}", line)?;
+                }
+            }
+            Data::Matching { range, parsed, .. } => {
+                let before = &line[0..range.start];
+                let during = &line[range.clone()];
+                let during = colorize(opt, &hist, &thresholds, during, parsed);
+                let after = &line[range.end..];
+                writeln!(out, "{}{}{}", before, during, after)?;
+            }
+        }
+    }
+    out.flush()?;
+
+    if opt.debug {
+        print_debug_stats(&hist, &opt.quantiles);
+    }
+
+    Ok(())
+}
+
+/// Dispatch to the mmap-backed path under the same conditions `main` does.
+fn run_streaming(opt: &Opt, re: &Regex, file: &PathBuf) -> Result<()> {
+    if opt.mmap || file.metadata().map(|m| m.is_file()).unwrap_or(false) {
+        run_streaming_mapped(opt, re, file)
+    } else {
+        run_streaming_buffered(opt, re, file)
+    }
+}
+
+/// Separator printed between disjoint context blocks, mirroring ripgrep.
+const CONTEXT_SEPARATOR: &str = "--";
+
+fn filter_and_sort(
+    data: Vec<Data<'_>>,
+    matching: bool,
+    sorting: Sorting,
+    before_context: usize,
+    after_context: usize,
+) -> Vec<Data<'_>> {
     match (matching, sorting) {
         (false, Sorting::Original) => data,
-        (true, Sorting::Original) => data
+        (true, Sorting::Original) if before_context == 0 && after_context == 0 => data
             .into_iter()
             .filter(|d| matches!(d, Data::Matching { .. }))
             .collect::<Vec<_>>(),
+        (true, Sorting::Original) => {
+            let len = data.len();
+            let mut windows: Vec<(usize, usize)> = data
+                .iter()
+                .enumerate()
+                .filter(|(_, d)| matches!(d, Data::Matching { .. }))
+                .map(|(i, _)| {
+                    let start = i.saturating_sub(before_context);
+                    let end = i.saturating_add(after_context).min(len.saturating_sub(1));
+                    (start, end)
+                })
+                .collect();
+            windows.sort_unstable();
+
+            let mut coalesced: Vec<(usize, usize)> = Vec::new();
+            for (start, end) in windows.drain(..) {
+                match coalesced.last_mut() {
+                    Some(last) if start <= last.1 + 1 => last.1 = last.1.max(end),
+                    _ => coalesced.push((start, end)),
+                }
+            }
+
+            let mut data: Vec<Option<Data<'_>>> = data.into_iter().map(Some).collect();
+            let mut out = Vec::new();
+            for (window_idx, (start, end)) in coalesced.into_iter().enumerate() {
+                if window_idx > 0 {
+                    out.push(Data::NotMatching(CONTEXT_SEPARATOR));
+                }
+                out.extend(data[start..=end].iter_mut().filter_map(Option::take));
+            }
+            out
+        }
         (_, Sorting::Asc) | (_, Sorting::Desc) => {
             let mut data = data
                 .into_iter()
@@ -148,55 +547,88 @@ fn filter_and_sort(data: Vec<Data>, matching: bool, sorting: Sorting) -> Vec<Dat
 fn main() -> Result<()> {
     let opt = Opt::from_args();
     let re = Regex::new(&opt.expr)?;
+    validate_group(&re, opt.group)?;
 
     if opt.force_colors {
         console::set_colors_enabled(opt.force_colors);
     }
 
-    let data = match &opt.input {
+    let context_requested = opt.before_context > 0 || opt.after_context > 0 || opt.context > 0;
+    if context_requested && opt.sorting != Sorting::Original {
+        eprintln!("Warning: -A/-B/-C have no effect with sorted output; ignoring.");
+    }
+    let (before_context, after_context) = if opt.sorting == Sorting::Original {
+        (
+            opt.before_context.max(opt.context),
+            opt.after_context.max(opt.context),
+        )
+    } else {
+        (0, 0)
+    };
+
+    // Original-order file input doesn't need every line resident before it
+    // can be colorized, just the histogram; Asc/Desc inherently need all
+    // rows in memory to sort, so they keep the buffer-everything path below.
+    // Context lines need lookahead/lookback across the whole file too, so
+    // they also fall back to the buffer-everything path.
+    if opt.sorting == Sorting::Original && opt.group.is_none() && !opt.mmap && !context_requested {
+        if let Some(file) = &opt.input {
+            return run_streaming(&opt, &re, file);
+        }
+    }
+
+    let source = match &opt.input {
+        Some(file) if opt.mmap || file.metadata().map(|m| m.is_file()).unwrap_or(false) => {
+            let f = File::open(file)?;
+            // SAFETY: `memmap2::Mmap::map` is unsafe because the mapping
+            // becomes invalid (and further reads are UB) if `f` is truncated
+            // or otherwise modified by another process while we hold the
+            // mapping. `f` is a file we just opened read-only and don't hand
+            // out elsewhere, so nothing in this process races it; we accept
+            // the same external-modification risk any other log-scanning
+            // tool reading this path would.
+            let mapped = unsafe { memmap2::Mmap::map(&f)? };
+            LineSource::Mapped(mapped)
+        }
         Some(file) => {
             let f = File::open(file)?;
-            io::BufReader::with_capacity(1 * 1024 * 1024, f)
-                .lines()
-                .map(|line| line.unwrap())
-                .collect::<Vec<_>>()
+            LineSource::Owned(
+                io::BufReader::with_capacity(1 * 1024 * 1024, f)
+                    .lines()
+                    .map(|line| line.unwrap())
+                    .collect::<Vec<_>>(),
+            )
         }
         None => {
             let stdin = io::stdin();
-            BufReader::with_capacity(8 * 1024 * 1024, stdin.lock())
-                .lines()
-                .map(|line| line.unwrap())
-                .collect::<Vec<_>>()
+            LineSource::Owned(
+                BufReader::with_capacity(8 * 1024 * 1024, stdin.lock())
+                    .lines()
+                    .map(|line| line.unwrap())
+                    .collect::<Vec<_>>(),
+            )
         }
     };
+    let lines = source.lines();
 
     let mut hist = Histogram::<u64>::new(5)?;
 
-    let data = data
+    let data = lines
         .into_par_iter()
-        .map_with(re, |re, line| match_line(&re, line))
+        .map_with(re, |re, line| match_line(re, line, opt.group))
         .collect::<Vec<_>>();
 
+    if opt.group.is_some() {
+        let grouped = group_histograms(&data)?;
+        return print_group_report(&grouped);
+    }
+
     data.iter().for_each(|d| match d {
         Data::Matching { parsed, .. } => hist += *parsed,
         _ => {}
     });
 
-    let p99 = hist.value_at_quantile(0.99);
-    let p90 = hist.value_at_quantile(0.90);
-    let p50 = hist.value_at_quantile(0.50);
-
-    let to_percentile = |val| {
-        if p99 <= val {
-            Percentile::P99
-        } else if p90 <= val {
-            Percentile::P90
-        } else if p50 <= val {
-            Percentile::P50
-        } else {
-            Percentile::Other
-        }
-    };
+    let thresholds = build_thresholds(&hist, &opt.quantiles);
 
     // println! grabs the stdout lock each time, so we'll grab it here
     // and use BufWriter/writeln to reduce the amount of times we need to grab
@@ -207,7 +639,7 @@ fn main() -> Result<()> {
     // Buffering at 1MB took 33ms, with 2 write.
     let stdout = io::stdout();
     let mut out = BufWriter::with_capacity(8 * 1024 * 1024, stdout.lock());
-    filter_and_sort(data, opt.matching, opt.sorting)
+    filter_and_sort(data, opt.matching, opt.sorting, before_context, after_context)
         .into_iter()
         .for_each(|data| match data {
             Data::NotMatching(line) => writeln!(out, "{}", line).unwrap(),
@@ -215,20 +647,11 @@ fn main() -> Result<()> {
                 line,
                 range,
                 parsed,
+                ..
             } => {
                 let before = &line[0..range.start];
                 let during = &line[range.clone()];
-                let during = match opt.highlight {
-                    true => style(during).yellow(),
-                    false => {
-                        let p = to_percentile(parsed);
-                        Style::from(p).apply_to(during)
-                    }
-                };
-                let during = match opt.bold {
-                    true => during.bold(),
-                    false => during,
-                };
+                let during = colorize(&opt, &hist, &thresholds, during, parsed);
                 let after = &line[range.end..];
                 writeln!(out, "{}{}{}", before, during, after).unwrap()
             }
@@ -236,10 +659,7 @@ fn main() -> Result<()> {
     out.flush()?;
 
     if opt.debug {
-        println!("Number of samples: {}", hist.len());
-        println!("99'th percentile:  {}", p99);
-        println!("90'th percentile:  {}", p90);
-        println!("50'th percentile:  {}", p50);
+        print_debug_stats(&hist, &opt.quantiles);
     }
 
     Ok(())
@@ -249,86 +669,231 @@ fn main() -> Result<()> {
 mod tests {
     use super::*;
 
-    fn data1() -> Data {
+    #[test]
+    fn test_default_quantile_colors_match_legacy_palette() {
+        console::set_colors_enabled(true);
+        let mut hist = Histogram::<u64>::new(5).unwrap();
+        for v in 1..=100u64 {
+            hist += v;
+        }
+        let thresholds = build_thresholds(&hist, &[0.99, 0.9, 0.5]);
+        let p99 = hist.value_at_quantile(0.99);
+        let p90 = hist.value_at_quantile(0.90);
+        let p50 = hist.value_at_quantile(0.50);
+
+        assert_eq!(
+            style_for_value(p99, &thresholds).apply_to("x").to_string(),
+            Style::new().red().apply_to("x").to_string(),
+        );
+        assert_eq!(
+            style_for_value(p90, &thresholds).apply_to("x").to_string(),
+            Style::new().yellow().apply_to("x").to_string(),
+        );
+        assert_eq!(
+            style_for_value(p50, &thresholds).apply_to("x").to_string(),
+            Style::new().green().apply_to("x").to_string(),
+        );
+        // Below every configured quantile: historically `Percentile::Other`,
+        // which was blue -- must stay blue, not shift to the next color in
+        // the cycle (cyan) just because the ladder is now dynamic.
+        assert_eq!(
+            style_for_value(0, &thresholds).apply_to("x").to_string(),
+            Style::new().blue().apply_to("x").to_string(),
+        );
+    }
+
+    fn data1() -> Data<'static> {
         Data::Matching {
-            line: "1".to_string(),
+            line: "1",
             range: 0..1,
             parsed: 1,
+            key: None,
         }
     }
 
-    fn data5() -> Data {
+    fn data5() -> Data<'static> {
         Data::Matching {
-            line: "5".to_string(),
+            line: "5",
             range: 0..1,
             parsed: 5,
+            key: None,
         }
     }
 
-    fn data10() -> Data {
+    fn data10() -> Data<'static> {
         Data::Matching {
-            line: "10".to_string(),
+            line: "10",
             range: 0..2,
             parsed: 10,
+            key: None,
         }
     }
 
-    fn hello() -> Data {
-        Data::NotMatching("hello".to_string())
+    fn hello() -> Data<'static> {
+        Data::NotMatching("hello")
     }
 
-    fn world() -> Data {
-        Data::NotMatching("world".to_string())
+    fn world() -> Data<'static> {
+        Data::NotMatching("world")
     }
 
-    fn sample_data() -> Vec<Data> {
+    fn sample_data() -> Vec<Data<'static>> {
         vec![data5(), hello(), data10(), world(), data1()]
     }
 
+    #[test]
+    fn test_mmap_lines_empty() {
+        assert_eq!(mmap_lines(b""), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn test_mmap_lines_no_trailing_newline() {
+        assert_eq!(mmap_lines(b"one\ntwo\nthree"), vec!["one", "two", "three"]);
+    }
+
+    #[test]
+    fn test_mmap_lines_trailing_newline() {
+        assert_eq!(mmap_lines(b"one\ntwo\n"), vec!["one", "two"]);
+    }
+
+    #[test]
+    fn test_mmap_lines_single_line() {
+        assert_eq!(mmap_lines(b"one"), vec!["one"]);
+    }
+
+    #[test]
+    fn test_mmap_lines_strips_embedded_cr() {
+        // Matches std::io::BufRead::lines()'s CRLF handling, so Owned and
+        // Mapped input produce identical lines for the same CRLF file.
+        assert_eq!(mmap_lines(b"one\r\ntwo\r\n"), vec!["one", "two"]);
+        assert_eq!(mmap_lines(b"one\r\ntwo"), vec!["one", "two"]);
+    }
+
+    #[test]
+    fn test_trim_trailing_cr() {
+        assert_eq!(trim_trailing_cr("foo\r"), "foo");
+        assert_eq!(trim_trailing_cr("foo"), "foo");
+        assert_eq!(trim_trailing_cr(""), "");
+    }
+
     #[test]
     fn test_match_line() {
         let re = Regex::new(r"(\d+)").unwrap();
-        assert_eq!(
-            Data::NotMatching("Hello".to_string()),
-            match_line(&re, "Hello".to_string())
-        );
+        assert_eq!(Data::NotMatching("Hello"), match_line(&re, "Hello", None));
         assert_eq!(
             Data::Matching {
-                line: "123".to_string(),
+                line: "123",
                 range: 0..3,
                 parsed: 123,
+                key: None,
             },
-            match_line(&re, "123".to_string())
+            match_line(&re, "123", None)
         );
     }
 
     #[test]
     fn test_match_line_bad_regex() {
         let re = Regex::new(r"(\D+)").unwrap();
-        assert_eq!(
-            Data::NotMatching("Hello".to_string()),
-            match_line(&re, "Hello".to_string())
-        );
-        assert_eq!(
-            Data::NotMatching("123".to_string()),
-            match_line(&re, "123".to_string())
-        );
+        assert_eq!(Data::NotMatching("Hello"), match_line(&re, "Hello", None));
+        assert_eq!(Data::NotMatching("123"), match_line(&re, "123", None));
     }
 
     #[test]
     fn test_match_line_no_capture() {
         let re = Regex::new(r"\d+").unwrap();
+        assert_eq!(Data::NotMatching("123"), match_line(&re, "123", None));
+    }
+
+    #[test]
+    fn test_match_line_group_key() {
+        let re = Regex::new(r"(\d+) (\w+)").unwrap();
         assert_eq!(
-            Data::NotMatching("123".to_string()),
-            match_line(&re, "123".to_string())
+            Data::Matching {
+                line: "42 host1",
+                range: 0..2,
+                parsed: 42,
+                key: Some("host1".to_string()),
+            },
+            match_line(&re, "42 host1", Some(2))
         );
     }
 
+    #[test]
+    fn test_validate_group_accepts_in_range() {
+        let re = Regex::new(r"(\d+) (\w+)").unwrap();
+        assert!(validate_group(&re, None).is_ok());
+        assert!(validate_group(&re, Some(0)).is_ok());
+        assert!(validate_group(&re, Some(1)).is_ok());
+        assert!(validate_group(&re, Some(2)).is_ok());
+    }
+
+    #[test]
+    fn test_validate_group_rejects_out_of_range() {
+        let re = Regex::new(r"(\d+) (\w+)").unwrap();
+        assert!(validate_group(&re, Some(3)).is_err());
+        assert!(validate_group(&re, Some(99)).is_err());
+    }
+
+    #[test]
+    fn test_group_histograms_merges_per_key_counts() {
+        let data = vec![
+            Data::Matching {
+                line: "1",
+                range: 0..1,
+                parsed: 10,
+                key: Some("host1".to_string()),
+            },
+            Data::Matching {
+                line: "2",
+                range: 0..1,
+                parsed: 20,
+                key: Some("host2".to_string()),
+            },
+            Data::Matching {
+                line: "3",
+                range: 0..1,
+                parsed: 30,
+                key: Some("host1".to_string()),
+            },
+            Data::NotMatching("ignored"),
+            Data::Matching {
+                line: "4",
+                range: 0..1,
+                parsed: 40,
+                key: None,
+            },
+        ];
+
+        let grouped = group_histograms(&data).unwrap();
+        assert_eq!(grouped.len(), 2);
+        assert_eq!(grouped["host1"].len(), 2);
+        assert_eq!(grouped["host1"].min(), 10);
+        assert_eq!(grouped["host1"].max(), 30);
+        assert_eq!(grouped["host2"].len(), 1);
+        assert_eq!(grouped["host2"].min(), 20);
+    }
+
+    #[test]
+    fn test_group_histograms_empty_input() {
+        let grouped = group_histograms(&[]).unwrap();
+        assert!(grouped.is_empty());
+    }
+
+    #[test]
+    fn test_format_group_line() {
+        let mut h = Histogram::<u64>::new(5).unwrap();
+        h += 10;
+        h += 20;
+        let line = format_group_line("host1", &h);
+        assert!(line.starts_with("{\"key\":\"host1\",\"count\":2,\"min\":10,"));
+        assert!(line.contains("\"max\":20"));
+    }
+
     #[test]
     fn test_no_matching_no_sorting() {
         assert_eq!(
             sample_data(),
-            filter_and_sort(sample_data(), false, Sorting::Original),
+            filter_and_sort(sample_data(), false, Sorting::Original, 0, 0),
         );
     }
 
@@ -336,7 +901,7 @@ mod tests {
     fn test_matching_no_sorting() {
         assert_eq!(
             vec![data5(), data10(), data1()],
-            filter_and_sort(sample_data(), true, Sorting::Original),
+            filter_and_sort(sample_data(), true, Sorting::Original, 0, 0),
         );
     }
 
@@ -344,7 +909,7 @@ mod tests {
     fn test_no_matching_sorting_desc() {
         assert_eq!(
             vec![data10(), data5(), data1()],
-            filter_and_sort(sample_data(), false, Sorting::Desc),
+            filter_and_sort(sample_data(), false, Sorting::Desc, 0, 0),
         );
     }
 
@@ -352,7 +917,58 @@ mod tests {
     fn test_matching_sorting_asc() {
         assert_eq!(
             vec![data1(), data5(), data10()],
-            filter_and_sort(sample_data(), true, Sorting::Asc),
+            filter_and_sort(sample_data(), true, Sorting::Asc, 0, 0),
+        );
+    }
+
+    // hello, data5 (match), world, hello, data1 (match), world
+    fn context_data() -> Vec<Data<'static>> {
+        vec![hello(), data5(), world(), hello(), data1(), world()]
+    }
+
+    #[test]
+    fn test_matching_with_after_context() {
+        assert_eq!(
+            vec![
+                data5(),
+                world(),
+                Data::NotMatching(CONTEXT_SEPARATOR),
+                data1(),
+                world(),
+            ],
+            filter_and_sort(context_data(), true, Sorting::Original, 0, 1),
+        );
+    }
+
+    #[test]
+    fn test_matching_with_before_context() {
+        assert_eq!(
+            vec![
+                hello(),
+                data5(),
+                Data::NotMatching(CONTEXT_SEPARATOR),
+                hello(),
+                data1(),
+            ],
+            filter_and_sort(context_data(), true, Sorting::Original, 1, 0),
+        );
+    }
+
+    #[test]
+    fn test_matching_context_coalesces_overlapping_windows() {
+        // Both matches pull in enough neighbors on each side that their
+        // windows touch, so they merge into one block with no separator.
+        assert_eq!(
+            context_data(),
+            filter_and_sort(context_data(), true, Sorting::Original, 1, 1),
+        );
+    }
+
+    #[test]
+    fn test_matching_with_huge_after_context_does_not_overflow() {
+        assert_eq!(
+            vec![data5(), world(), hello(), data1(), world()],
+            filter_and_sort(context_data(), true, Sorting::Original, 0, usize::MAX),
         );
     }
 }